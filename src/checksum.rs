@@ -0,0 +1,76 @@
+//! Incremental CRC32 used to detect corrupt or partially-written
+//! harmonic data, both per-event and per-run (see [`crate::manifest`]).
+const POLY: u32 = 0xEDB8_8320;
+
+fn table_entry(mut value: u32) -> u32 {
+    let mut i = 0;
+    while i < 8 {
+        value = if value & 1 != 0 {
+            (value >> 1) ^ POLY
+        } else {
+            value >> 1
+        };
+        i += 1;
+    }
+    value
+}
+
+/// An incremental CRC32 (IEEE) accumulator.
+///
+/// Bytes can be fed in piecemeal as they're produced (one event at a
+/// time, one trace at a time), which is what lets us compute both a
+/// per-event checksum and a running per-run checksum without buffering
+/// a whole run file's worth of trace data.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let index = ((self.state ^ byte as u32) & 0xFF) as u32;
+            self.state = table_entry(index) ^ (self.state >> 8);
+        }
+    }
+
+    pub fn finalize(&self) -> u32 {
+        self.state ^ 0xFFFF_FFFF
+    }
+}
+
+/// Compute the CRC32 of `bytes` in one shot.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Crc32::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Feed `samples` into both a per-event hasher and the run-level
+/// hasher it's part of, one little-endian sample at a time.
+///
+/// This is the single place that defines the byte-order contract for
+/// trace checksums, so the write path (hashing in-memory samples) and
+/// the read-back path (hashing samples re-read from disk, in
+/// [`crate::format`]) can't drift apart from each other.
+pub fn update_i16_samples(
+    event_hasher: &mut Crc32,
+    run_hasher: &mut Crc32,
+    samples: impl Iterator<Item = i16>,
+) {
+    for sample in samples {
+        let bytes = sample.to_le_bytes();
+        event_hasher.update(&bytes);
+        run_hasher.update(&bytes);
+    }
+}
@@ -0,0 +1,71 @@
+//! Compression options for the bulk trace datasets written by a
+//! [`HarmonicWriter`](super::writer::HarmonicWriter).
+//!
+//! Waveform data is highly compressible, so trace datasets are written
+//! chunked with an optional filter pipeline rather than as flat,
+//! uncompressed arrays.
+use hdf5_metno::dataset::DatasetBuilder;
+
+/// Which filter (if any) to apply to a chunked trace dataset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Store chunked but uncompressed.
+    #[default]
+    None,
+    /// Gzip/deflate at the given level (0-9).
+    Gzip(u8),
+    /// Szip, for sites where it's available.
+    Szip,
+}
+
+/// Compression configuration for trace datasets: the filter to apply,
+/// and whether to run the byte-shuffle filter ahead of it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompressionConfig {
+    pub compression: Compression,
+    pub shuffle: bool,
+}
+
+impl CompressionConfig {
+    /// No compression, no shuffle. Non-empty trace datasets are still
+    /// stored chunked (see [`apply`](Self::apply)), not contiguous.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Apply this configuration to a dataset builder, chunking it
+    /// according to `shape`. HDF5 rejects a zero-sized chunk dimension,
+    /// so an empty array (or one with a zero-length axis) falls back
+    /// to a plain contiguous, uncompressed dataset instead.
+    pub fn apply<'a>(&self, builder: DatasetBuilder<'a>, shape: &[usize]) -> DatasetBuilder<'a> {
+        if shape.is_empty() || shape.contains(&0) {
+            return builder;
+        }
+
+        let mut builder = builder.chunk(chunk_shape(shape));
+
+        if self.shuffle {
+            builder = builder.shuffle();
+        }
+
+        match self.compression {
+            Compression::None => builder,
+            Compression::Gzip(level) => builder.deflate(level),
+            Compression::Szip => builder.szip(Default::default(), Default::default()),
+        }
+    }
+}
+
+/// Pick a chunk shape for a trace array: chunk one row (e.g. one pad's
+/// or one channel's trace) at a time, keeping the remaining dimensions
+/// whole, so a single trace can be read back without decompressing its
+/// neighbors. Callers must ensure `shape` is non-empty with no
+/// zero-length axis.
+fn chunk_shape(shape: &[usize]) -> Vec<usize> {
+    match shape.split_first() {
+        Some((_, rest)) if !rest.is_empty() => {
+            std::iter::once(1).chain(rest.iter().copied()).collect()
+        }
+        _ => shape.to_vec(),
+    }
+}
@@ -0,0 +1,200 @@
+//! Encoding of `MergerEvent`s into the on-disk AT-TPC merger layout.
+//!
+//! `HarmonicWriter` writes events through a [`MergerFormat`]
+//! implementation, which owns the group/dataset layout of a harmonic
+//! file; the writer itself drives file rotation and sizing. Reading
+//! events back (resuming in `Append` mode, and
+//! [`crate::manifest::verify`]) does not go through the trait: it always
+//! reads the 0.2.0 layout via [`verify_event_trace_checksum`].
+use super::checksum::{update_i16_samples, Crc32};
+use super::compression::CompressionConfig;
+use super::reader::MergerEvent;
+use color_eyre::eyre::Result;
+use hdf5_metno::types::VarLenUnicode;
+use hdf5_metno::{File, Group};
+use std::str::FromStr;
+
+/// A specific version of the AT-TPC merger file layout.
+///
+/// Implementations own the group/attribute/dataset naming scheme for a
+/// single harmonic file when writing; `HarmonicWriter` calls these
+/// methods at the appropriate points in a file's lifecycle. Read-back
+/// of written events uses the 0.2.0 layout whatever format is in use
+/// (see [`verify_event_trace_checksum`]).
+pub trait MergerFormat {
+    /// Identifier stamped into the `version` attribute of each file.
+    fn format_version(&self) -> &str;
+
+    /// Set up whatever groups/attributes a freshly created file needs
+    /// before any events are written.
+    fn init_file(&self, file: &File) -> Result<()>;
+
+    /// Write a single event at `event_idx` into `file`. `compression`
+    /// governs how the bulk trace datasets are chunked/compressed.
+    /// Returns a CRC32 checksum over the event's raw trace bytes, which
+    /// is also stamped onto the event's group as a `checksum` attribute
+    /// so [`crate::manifest::verify`] can recompute it later. The same
+    /// raw trace bytes, in the same order, are fed into `run_hasher` so
+    /// the run's overall content hash covers actual trace data rather
+    /// than a hash of per-event checksums.
+    fn write_event(
+        &self,
+        file: &File,
+        event: &MergerEvent,
+        event_idx: u64,
+        compression: &CompressionConfig,
+        run_hasher: &mut Crc32,
+    ) -> Result<u32>;
+
+    /// Write the metadata that can only be known once a file is done
+    /// being written to (e.g. the final event count).
+    fn finish_file(&self, file: &File, max_event: u64) -> Result<()>;
+}
+
+/// The slightly modified 0.2.0 merger format (see README).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MergerFormatV0_2_0;
+
+impl MergerFormat for MergerFormatV0_2_0 {
+    fn format_version(&self) -> &str {
+        "0.2.0"
+    }
+
+    fn init_file(&self, file: &File) -> Result<()> {
+        let harmonizer_version = format!(
+            "{}:{}:{}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            self.format_version()
+        );
+
+        let events_group = file.create_group("events")?;
+        events_group
+            .new_attr::<u64>()
+            .create("min_event")?
+            .write_scalar(&0)?;
+        events_group.new_attr::<u64>().create("max_event")?;
+        events_group
+            .new_attr::<VarLenUnicode>()
+            .create("version")?
+            .write_scalar(&VarLenUnicode::from_str(&harmonizer_version).unwrap())?;
+        Ok(())
+    }
+
+    fn write_event(
+        &self,
+        file: &File,
+        event: &MergerEvent,
+        event_idx: u64,
+        compression: &CompressionConfig,
+        run_hasher: &mut Crc32,
+    ) -> Result<u32> {
+        let event_group = file
+            .group("events")?
+            .create_group(&format!("event_{event_idx}"))?;
+
+        event_group
+            .new_attr::<i32>()
+            .create("orig_run")?
+            .write_scalar(&event.run_number)?;
+
+        event_group
+            .new_attr::<u64>()
+            .create("orig_event")?
+            .write_scalar(&event.event)?;
+
+        let mut hasher = Crc32::new();
+
+        if let Some(get) = event.get.as_ref() {
+            update_i16_samples(&mut hasher, run_hasher, get.traces.iter().copied());
+
+            let traces = compression
+                .apply(event_group.new_dataset_builder(), get.traces.shape())
+                .with_data(&get.traces)
+                .create("get_traces")?;
+            traces
+                .new_attr::<u32>()
+                .create("id")?
+                .write_scalar(&get.id)?;
+            traces
+                .new_attr::<u64>()
+                .create("timestamp")?
+                .write_scalar(&get.timestamp)?;
+            traces
+                .new_attr::<u64>()
+                .create("timestamp_other")?
+                .write_scalar(&get.timestamp_other)?;
+        }
+
+        if let Some(frib) = event.frib.as_ref() {
+            update_i16_samples(&mut hasher, run_hasher, frib.traces.iter().copied());
+            update_i16_samples(&mut hasher, run_hasher, frib.coincidence.iter().copied());
+
+            let frib_group = event_group.create_group("frib_physics")?;
+            frib_group
+                .new_attr::<u32>()
+                .create("event")?
+                .write_scalar(&frib.event)?;
+            frib_group
+                .new_attr::<u32>()
+                .create("timestamp")?
+                .write_scalar(&frib.timestamp)?;
+            compression
+                .apply(frib_group.new_dataset_builder(), frib.traces.shape())
+                .with_data(&frib.traces)
+                .create("1903")?;
+            compression
+                .apply(frib_group.new_dataset_builder(), frib.coincidence.shape())
+                .with_data(&frib.coincidence)
+                .create("977")?;
+        }
+
+        let checksum = hasher.finalize();
+        event_group
+            .new_attr::<u32>()
+            .create("checksum")?
+            .write_scalar(&checksum)?;
+
+        Ok(checksum)
+    }
+
+    fn finish_file(&self, file: &File, max_event: u64) -> Result<()> {
+        file.group("events")?
+            .attr("max_event")?
+            .write_scalar(&max_event)?;
+        Ok(())
+    }
+}
+
+/// Re-read an already-written event's trace datasets (`get_traces`,
+/// `frib_physics/1903`, `frib_physics/977`) back from disk and
+/// recompute its CRC32 checksum from the actual bytes on disk, in the
+/// same order [`MergerFormatV0_2_0::write_event`] hashed them in. The
+/// same bytes are fed into `run_hasher`, so a run's content hash can be
+/// reconstructed (by [`crate::manifest::verify`] or
+/// [`crate::writer::WriterState`] resuming an `Append`) by replaying
+/// real trace data rather than trusting a previously stored checksum.
+pub(crate) fn verify_event_trace_checksum(
+    event_group: &Group,
+    run_hasher: &mut Crc32,
+) -> Result<u32> {
+    let mut hasher = Crc32::new();
+
+    if let Ok(dataset) = event_group.dataset("get_traces") {
+        let samples = dataset.read_raw::<i16>()?;
+        update_i16_samples(&mut hasher, run_hasher, samples.into_iter());
+    }
+
+    if let Ok(frib_group) = event_group.group("frib_physics") {
+        if let Ok(dataset) = frib_group.dataset("1903") {
+            let samples = dataset.read_raw::<i16>()?;
+            update_i16_samples(&mut hasher, run_hasher, samples.into_iter());
+        }
+        if let Ok(dataset) = frib_group.dataset("977") {
+            let samples = dataset.read_raw::<i16>()?;
+            update_i16_samples(&mut hasher, run_hasher, samples.into_iter());
+        }
+    }
+
+    Ok(hasher.finalize())
+}
@@ -0,0 +1,181 @@
+//! A crash-safe manifest of finalized run files.
+//!
+//! `finish_file` previously only recorded a run's `max_event` inside
+//! the file itself, so a crash mid-run left no way to tell which run
+//! files were actually complete. The manifest is a small JSON sidecar
+//! in `harmonic_path` that records one entry per finalized run; it is
+//! what makes [`OpenMode::Append`](super::writer::OpenMode::Append) and
+//! [`verify`] safe.
+use super::checksum::Crc32;
+use super::format::verify_event_trace_checksum;
+use super::reader::construct_run_path;
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A single finalized run file, as recorded at `finish_file` time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub run: i32,
+    pub path: PathBuf,
+    pub event_count: u64,
+    /// CRC32 over the raw trace bytes of every event in the run, in
+    /// event order, accumulated incrementally as each event is written
+    /// (see [`MergerFormat::write_event`](super::format::MergerFormat::write_event)).
+    /// [`verify`] recomputes this from the trace datasets themselves, so
+    /// it actually detects corrupt or partially-written trace data
+    /// rather than just a mutated `checksum` attribute.
+    pub content_hash: u32,
+}
+
+/// The sidecar manifest for a harmonic output directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// The manifest's path for a given harmonic output directory.
+    pub fn path_for(harmonic_path: &Path) -> PathBuf {
+        harmonic_path.join("manifest.json")
+    }
+
+    /// Load the manifest for `harmonic_path`, or an empty one if it
+    /// doesn't exist yet.
+    pub fn load(harmonic_path: &Path) -> Result<Self> {
+        let path = Self::path_for(harmonic_path);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Record a newly finalized run file and persist the manifest.
+    pub fn record(&mut self, harmonic_path: &Path, entry: ManifestEntry) -> Result<()> {
+        self.entries.retain(|e| e.run != entry.run);
+        self.entries.push(entry);
+        self.save(harmonic_path)
+    }
+
+    /// Look up the manifest entry for a given run, if it was ever
+    /// finalized.
+    pub fn entry_for_run(&self, run: i32) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| e.run == run)
+    }
+
+    /// Write the manifest via a temp file + rename so a crash mid-write
+    /// can never leave `manifest.json` truncated or half-written.
+    fn save(&self, harmonic_path: &Path) -> Result<()> {
+        let path = Self::path_for(harmonic_path);
+        let tmp_path = path.with_extension("json.tmp");
+
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// The health of a single run file, as determined by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunHealth {
+    /// Present in the manifest and matches it.
+    Complete,
+    /// The run file exists on disk but has no manifest entry, so it
+    /// was never finalized (e.g. a crash mid-run).
+    MissingFromManifest,
+    /// The manifest's event count doesn't match the file's `max_event`.
+    EventCountMismatch { expected: u64, actual: u64 },
+    /// A single event's trace bytes don't hash to its stored `checksum`
+    /// attribute, so that event's data is corrupt.
+    EventChecksumMismatch {
+        event: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// Every event's own checksum checks out, but the run's overall
+    /// content hash doesn't match the manifest — e.g. events were
+    /// dropped, reordered, or appended outside of `HarmonicWriter`.
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+/// The verification result for one run file.
+#[derive(Debug, Clone)]
+pub struct RunStatus {
+    pub run: i32,
+    pub path: PathBuf,
+    pub health: RunHealth,
+}
+
+/// Cross-check every run file under `harmonic_path` against the
+/// manifest, reporting which ones are missing or inconsistent.
+pub fn verify(harmonic_path: &Path) -> Result<Vec<RunStatus>> {
+    let manifest = Manifest::load(harmonic_path)?;
+    let mut statuses = Vec::new();
+
+    let mut run = 0;
+    loop {
+        let path = construct_run_path(harmonic_path, run);
+        if !path.exists() {
+            break;
+        }
+
+        let health = match manifest.entry_for_run(run) {
+            None => RunHealth::MissingFromManifest,
+            Some(entry) => {
+                let file = hdf5_metno::File::open(&path)?;
+                let events_group = file.group("events")?;
+                let actual_count: u64 = events_group.attr("max_event")?.read_scalar()?;
+
+                if actual_count != entry.event_count {
+                    RunHealth::EventCountMismatch {
+                        expected: entry.event_count,
+                        actual: actual_count,
+                    }
+                } else {
+                    match run_content_hash(&events_group, actual_count)? {
+                        Err(mismatch) => mismatch,
+                        Ok(actual_hash) if actual_hash == entry.content_hash => RunHealth::Complete,
+                        Ok(actual_hash) => RunHealth::ChecksumMismatch {
+                            expected: entry.content_hash,
+                            actual: actual_hash,
+                        },
+                    }
+                }
+            }
+        };
+
+        statuses.push(RunStatus { run, path, health });
+        run += 1;
+    }
+
+    Ok(statuses)
+}
+
+/// Recompute a run's content hash from the actual trace bytes of every
+/// event, re-read from disk in the same order the writer accumulated
+/// them in. Returns `Ok(Err(health))` instead of the hash if a single
+/// event's trace bytes don't match its own stored `checksum` attribute,
+/// so the caller can report which event is corrupt rather than just
+/// that the run as a whole doesn't match.
+fn run_content_hash(
+    events_group: &hdf5_metno::Group,
+    event_count: u64,
+) -> Result<Result<u32, RunHealth>> {
+    let mut run_hasher = Crc32::new();
+    for event_idx in 0..event_count {
+        let event_group = events_group.group(&format!("event_{event_idx}"))?;
+        let expected: u32 = event_group.attr("checksum")?.read_scalar()?;
+        let actual = verify_event_trace_checksum(&event_group, &mut run_hasher)?;
+        if actual != expected {
+            return Ok(Err(RunHealth::EventChecksumMismatch {
+                event: event_idx,
+                expected,
+                actual,
+            }));
+        }
+    }
+    Ok(Ok(run_hasher.finalize()))
+}
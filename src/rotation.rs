@@ -0,0 +1,49 @@
+//! When a [`HarmonicWriter`](super::writer::HarmonicWriter) rotates to
+//! the next run file.
+use std::time::Duration;
+
+/// A single condition that can trigger rotation to the next run file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationTrigger {
+    /// Rotate once the current file reaches this many bytes on disk.
+    MaxBytes(u64),
+    /// Rotate once the current file holds this many events.
+    MaxEvents(u64),
+    /// Rotate once this much wall-clock time has passed since the
+    /// current file was initialized.
+    MaxDuration(Duration),
+}
+
+/// The set of conditions under which a writer rotates to the next run
+/// file; rotation happens when any one of them is reached.
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    triggers: Vec<RotationTrigger>,
+}
+
+impl RotationPolicy {
+    /// A policy with a single `MaxBytes` trigger, matching the
+    /// original fixed-byte-size rotation behavior.
+    pub fn max_bytes(harmonic_size: u64) -> Self {
+        Self {
+            triggers: vec![RotationTrigger::MaxBytes(harmonic_size)],
+        }
+    }
+
+    /// A policy evaluated from an arbitrary set of triggers; rotation
+    /// happens as soon as any one of them is reached.
+    pub fn new(triggers: Vec<RotationTrigger>) -> Self {
+        Self { triggers }
+    }
+
+    /// Whether the current file should be rotated, given its size on
+    /// disk, the number of events written to it, and how long it's
+    /// been open.
+    pub fn should_rotate(&self, current_bytes: u64, current_events: u64, age: Duration) -> bool {
+        self.triggers.iter().any(|trigger| match trigger {
+            RotationTrigger::MaxBytes(max) => current_bytes >= *max,
+            RotationTrigger::MaxEvents(max) => current_events >= *max,
+            RotationTrigger::MaxDuration(max) => age >= *max,
+        })
+    }
+}
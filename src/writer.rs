@@ -1,149 +1,388 @@
 //! Representation of a Writer for harmonic data
+use super::checksum::Crc32;
+use super::compression::CompressionConfig;
+use super::format::{self, MergerFormat};
+use super::manifest::{self, Manifest, ManifestEntry, RunStatus};
 use super::reader::{construct_run_path, MergerEvent};
-use color_eyre::eyre::Result;
-use hdf5_metno::types::VarLenUnicode;
+use super::rotation::RotationPolicy;
+use color_eyre::eyre::{eyre, Result};
 use hdf5_metno::File;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-/// Representation of a writer for harmonic data.
-/// It writes data with a slightly modified version of the
-/// 0.2.0 merger format (see README). Harmonic data is written
-/// to files, where each file has the same total amount of data
-/// (in bytes).
+/// Whether a new [`HarmonicWriter`] should start a fresh run from 0 or
+/// resume an interrupted harmonization job.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Start writing from run 0, overwriting each existing run file as
+    /// the writer reaches it. Run files beyond the last run written, and
+    /// their entries in an existing manifest, are left in place.
+    #[default]
+    Truncate,
+    /// Resume from the highest-numbered existing run file, continuing
+    /// to append events to it (or rotating to the next run if it's
+    /// already full).
+    Append,
+}
+
+/// How often the writer thread flushes the current file to disk,
+/// trading durability for speed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never flush explicitly; rely on the OS and on `close()`.
+    #[default]
+    Never,
+    /// Flush after every event.
+    EveryEvent,
+    /// Flush every `n` events.
+    EveryEvents(u64),
+}
+
+impl SyncPolicy {
+    fn should_sync(&self, current_event: u64) -> bool {
+        match self {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryEvent => true,
+            SyncPolicy::EveryEvents(n) => *n > 0 && current_event % n == 0,
+        }
+    }
+}
+
+/// How many pending events the writer thread's queue can hold before
+/// [`HarmonicWriter::write`] starts blocking the producer.
+const DEFAULT_QUEUE_DEPTH: usize = 256;
+
+enum Command {
+    Write(Box<MergerEvent>),
+    Close,
+}
+
+/// A writer for harmonic data.
+///
+/// Harmonic data is written to files, where each file has the same
+/// total amount of data (in bytes) by default (see [`RotationPolicy`]).
+/// The on-disk group/attribute/dataset layout is delegated to a
+/// [`MergerFormat`], so new AT-TPC merger layouts can be supported
+/// without changing the rotation/sizing logic here.
+///
+/// Apart from opening the first file, which `new` does on the caller's
+/// thread, all HDF5 work happens on a single background thread so that
+/// acquisition-rate data isn't serialized behind disk I/O on the
+/// caller's thread: `write` just pushes the event onto a bounded
+/// channel, applying backpressure once [`DEFAULT_QUEUE_DEPTH`] events
+/// are queued. The writer state, file handle included, is moved into
+/// the worker thread and never shared.
 #[derive(Debug)]
 pub struct HarmonicWriter {
+    sender: SyncSender<Command>,
+    worker: Option<JoinHandle<Result<()>>>,
+}
+
+impl HarmonicWriter {
+    /// Create a new writer, the first file to be written is initialized
+    /// using the given `format`. Trace datasets are chunked and, if
+    /// `compression` requests it, compressed. `mode` selects whether an
+    /// existing `harmonic_path` is overwritten or resumed. `rotation`
+    /// governs when the writer moves on to the next run file, and
+    /// `sync` governs how often it's flushed to disk.
+    pub fn new(
+        harmonic_path: &Path,
+        rotation: RotationPolicy,
+        format: Box<dyn MergerFormat + Send>,
+        compression: CompressionConfig,
+        mode: OpenMode,
+        sync: SyncPolicy,
+    ) -> Result<Self> {
+        let mut state = WriterState::new(harmonic_path, rotation, format, compression, mode)?;
+
+        let (sender, receiver) = sync_channel(DEFAULT_QUEUE_DEPTH);
+        let worker = std::thread::spawn(move || -> Result<()> {
+            for command in receiver {
+                match command {
+                    Command::Write(event) => state.write(*event, sync)?,
+                    Command::Close => break,
+                }
+            }
+            state.finish_file()
+        });
+
+        Ok(Self {
+            sender,
+            worker: Some(worker),
+        })
+    }
+
+    /// Queue a MergerEvent to be written. Returns an error only if the
+    /// writer thread has already stopped (e.g. after a prior write
+    /// error); the write itself happens asynchronously, so a write
+    /// that fails on the worker thread is only reported once `close`
+    /// joins that thread.
+    pub fn write(&self, event: MergerEvent) -> Result<()> {
+        self.sender
+            .send(Command::Write(Box::new(event)))
+            .map_err(|_| eyre!("harmonic writer thread has already stopped"))
+    }
+
+    /// Close the writer: drain the queue, let the worker thread finish
+    /// writing everything already queued, finalize the last file's
+    /// metadata, and join the thread.
+    pub fn close(&mut self) -> Result<()> {
+        // The sender stays alive in `self`, so the worker's
+        // `for command in receiver` loop never ends on its own; the
+        // explicit Close, queued behind every pending write, ends it
+        // once the queue drains.
+        let _ = self.sender.send(Command::Close);
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .map_err(|_| eyre!("harmonic writer thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Cross-check every run file under `harmonic_path` against its
+    /// manifest, flagging run files that are missing, incomplete, or
+    /// corrupt. See [`manifest::verify`].
+    pub fn verify(harmonic_path: &Path) -> Result<Vec<RunStatus>> {
+        manifest::verify(harmonic_path)
+    }
+}
+
+impl Drop for HarmonicWriter {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// The actual file lifecycle and HDF5 encoding logic, owned entirely by
+/// the background thread spawned in [`HarmonicWriter::new`].
+#[derive(Debug)]
+struct WriterState {
     harmonic_path: PathBuf,
     current_path: PathBuf,
     current_file: File,
     current_run: i32,
     current_event: u64,
-    harmonic_size: u64,
+    current_file_started_at: Instant,
+    rotation: RotationPolicy,
+    format: Box<dyn MergerFormat + Send>,
+    compression: CompressionConfig,
+    manifest: Manifest,
+    /// Running per-run content hash, accumulated directly from the raw
+    /// trace bytes of each event as it's written (see
+    /// [`MergerFormat::write_event`]), so it actually detects corrupt or
+    /// partially-written trace data rather than just a mutated
+    /// `checksum` attribute. Resuming into an existing file
+    /// ([`replay_run_hasher`](WriterState::replay_run_hasher)) and
+    /// [`manifest::verify`] both reconstruct it the same way, by
+    /// re-reading the trace datasets through
+    /// [`format::verify_event_trace_checksum`].
+    run_hasher: Crc32,
 }
 
-impl HarmonicWriter {
-    /// Create a new writer, the first file to be written is initialized.
-    pub fn new(harmonic_path: &Path, harmonic_size: u64) -> Result<Self> {
-        let current_run = 0;
+impl WriterState {
+    fn new(
+        harmonic_path: &Path,
+        rotation: RotationPolicy,
+        format: Box<dyn MergerFormat + Send>,
+        compression: CompressionConfig,
+        mode: OpenMode,
+    ) -> Result<Self> {
+        match mode {
+            OpenMode::Truncate => {
+                Self::new_truncated(harmonic_path, rotation, format, compression, 0, 0, true)
+            }
+            OpenMode::Append => Self::new_appended(harmonic_path, rotation, format, compression),
+        }
+    }
+
+    /// Find the highest existing run file under `harmonic_path`, if any.
+    fn highest_existing_run(harmonic_path: &Path) -> Option<i32> {
+        let mut run = 0;
+        let mut highest = None;
+        while construct_run_path(harmonic_path, run).exists() {
+            highest = Some(run);
+            run += 1;
+        }
+        highest
+    }
+
+    fn new_truncated(
+        harmonic_path: &Path,
+        rotation: RotationPolicy,
+        format: Box<dyn MergerFormat + Send>,
+        compression: CompressionConfig,
+        current_run: i32,
+        current_event: u64,
+        create_fresh: bool,
+    ) -> Result<Self> {
         let current_path = construct_run_path(harmonic_path, current_run);
-        let current_file = File::create(&current_path)?;
+        let current_file = if create_fresh {
+            File::create(&current_path)?
+        } else {
+            File::open_rw(&current_path)?
+        };
 
-        let writer = Self {
+        let run_hasher = if create_fresh {
+            Crc32::new()
+        } else {
+            Self::replay_run_hasher(&current_file, current_event)?
+        };
+
+        let state = Self {
             harmonic_path: harmonic_path.to_path_buf(),
             current_path,
             current_file,
             current_run,
-            current_event: 0,
-            harmonic_size,
+            current_event,
+            current_file_started_at: Instant::now(),
+            rotation,
+            format,
+            compression,
+            manifest: Manifest::load(harmonic_path)?,
+            run_hasher,
         };
 
-        writer.init_file()?;
+        if create_fresh {
+            state.init_file()?;
+        }
 
-        Ok(writer)
+        Ok(state)
     }
 
-    /// Write a MergerEvent.
-    pub fn write(&mut self, event: MergerEvent) -> Result<()> {
-        let event_group = self
-            .current_file
-            .group("events")?
-            .create_group(&format!("event_{}", self.current_event))?;
-
-        event_group
-            .new_attr::<i32>()
-            .create("orig_run")?
-            .write_scalar(&event.run_number)?;
-
-        event_group
-            .new_attr::<u64>()
-            .create("orig_event")?
-            .write_scalar(&event.event)?;
-
-        if let Some(get) = event.get.as_ref() {
-            let traces = event_group
-                .new_dataset_builder()
-                .with_data(&get.traces)
-                .create("get_traces")?;
-            traces
-                .new_attr::<u32>()
-                .create("id")?
-                .write_scalar(&get.id)?;
-            traces
-                .new_attr::<u64>()
-                .create("timestamp")?
-                .write_scalar(&get.timestamp)?;
-            traces
-                .new_attr::<u64>()
-                .create("timestamp_other")?
-                .write_scalar(&get.timestamp_other)?;
+    /// Reconstruct the running per-run checksum for a file we're
+    /// resuming into, by re-reading each of its already-written events'
+    /// trace datasets back from disk and recomputing their checksums, in
+    /// the same order they were accumulated in originally. Errors if a
+    /// recomputed checksum doesn't match the event's stored `checksum`
+    /// attribute, so a corrupted file is caught before `Append` resumes
+    /// writing into it.
+    fn replay_run_hasher(file: &File, event_count: u64) -> Result<Crc32> {
+        let events_group = file.group("events")?;
+        let mut run_hasher = Crc32::new();
+        for event_idx in 0..event_count {
+            let event_group = events_group.group(&format!("event_{event_idx}"))?;
+            let expected: u32 = event_group.attr("checksum")?.read_scalar()?;
+            let actual = format::verify_event_trace_checksum(&event_group, &mut run_hasher)?;
+            if actual != expected {
+                return Err(eyre!(
+                    "event {event_idx} trace checksum mismatch on resume (expected {expected}, recomputed {actual}); run recovery before resuming with Append"
+                ));
+            }
         }
+        Ok(run_hasher)
+    }
 
-        if let Some(frib) = event.frib.as_ref() {
-            let frib_group = event_group.create_group("frib_physics")?;
-            frib_group
-                .new_attr::<u32>()
-                .create("event")?
-                .write_scalar(&frib.event)?;
-            frib_group
-                .new_attr::<u32>()
-                .create("timestamp")?
-                .write_scalar(&frib.timestamp)?;
-            frib_group
-                .new_dataset_builder()
-                .with_data(&frib.traces)
-                .create("1903")?;
-            frib_group
-                .new_dataset_builder()
-                .with_data(&frib.coincidence)
-                .create("977")?;
+    /// Resume an interrupted harmonization job: reopen the highest
+    /// existing run file, pick up its event count, and either keep
+    /// appending to it or roll over to the next run if it's full. Only
+    /// the byte-size and event-count triggers of `rotation` are
+    /// consulted here; the max-duration trigger restarts its clock
+    /// from this call, since a file's age can't survive a restart.
+    fn new_appended(
+        harmonic_path: &Path,
+        rotation: RotationPolicy,
+        format: Box<dyn MergerFormat + Send>,
+        compression: CompressionConfig,
+    ) -> Result<Self> {
+        let Some(last_run) = Self::highest_existing_run(harmonic_path) else {
+            return Self::new_truncated(harmonic_path, rotation, format, compression, 0, 0, true);
+        };
+
+        let last_path = construct_run_path(harmonic_path, last_run);
+        let manifest = Manifest::load(harmonic_path)?;
+        let entry = manifest.entry_for_run(last_run).ok_or_else(|| {
+            eyre!(
+                "run file {} was never finalized (missing from the manifest); run recovery before resuming with Append",
+                last_path.display()
+            )
+        })?;
+        let max_event = entry.event_count;
+
+        let already_full =
+            rotation.should_rotate(last_path.metadata()?.len(), max_event, Duration::ZERO);
+
+        if already_full {
+            Self::new_truncated(
+                harmonic_path,
+                rotation,
+                format,
+                compression,
+                last_run + 1,
+                0,
+                true,
+            )
+        } else {
+            Self::new_truncated(
+                harmonic_path,
+                rotation,
+                format,
+                compression,
+                last_run,
+                max_event,
+                false,
+            )
         }
+    }
+
+    /// Write a single MergerEvent, rotating and flushing as needed.
+    fn write(&mut self, event: MergerEvent, sync: SyncPolicy) -> Result<()> {
+        self.format.write_event(
+            &self.current_file,
+            &event,
+            self.current_event,
+            &self.compression,
+            &mut self.run_hasher,
+        )?;
 
         self.current_event += 1;
 
-        if self.current_path.metadata()?.len() >= self.harmonic_size {
+        if sync.should_sync(self.current_event) {
+            self.current_file.flush()?;
+        }
+
+        let should_rotate = self.rotation.should_rotate(
+            self.current_path.metadata()?.len(),
+            self.current_event,
+            self.current_file_started_at.elapsed(),
+        );
+
+        if should_rotate {
             self.finish_file()?;
             self.current_event = 0;
             self.current_run += 1;
             self.current_path = construct_run_path(&self.harmonic_path, self.current_run);
             self.current_file = File::create(&self.current_path)?;
+            self.current_file_started_at = Instant::now();
+            self.run_hasher = Crc32::new();
             self.init_file()?;
         }
 
         Ok(())
     }
 
-    /// Close the writer, ensuring that the required metadata
-    /// is written to the current file.
-    pub fn close(&self) -> Result<()> {
-        self.finish_file()
-    }
-
     /// Initialize the current file
     fn init_file(&self) -> Result<()> {
-        let harmonizer_version =
-            format!("{}:{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
-
-        let events_group = self.current_file.create_group("events")?;
-        events_group
-            .new_attr::<u64>()
-            .create("min_event")?
-            .write_scalar(&0)?;
-        events_group.new_attr::<u64>().create("max_event")?;
-        events_group
-            .new_attr::<VarLenUnicode>()
-            .create("version")?
-            .write_scalar(&VarLenUnicode::from_str(&harmonizer_version).unwrap())?;
-        Ok(())
+        self.format.init_file(&self.current_file)
     }
 
-    /// Write the required metadata to the currently open file
-    /// when we are done with it.
-    fn finish_file(&self) -> Result<()> {
-        self.current_file
-            .group("events")?
-            .attr("max_event")?
-            .write_scalar(&self.current_event)?;
+    /// Write the required metadata to the currently open file when
+    /// we're done with it, and record it in the manifest so recovery
+    /// and `Append` mode can trust it later.
+    fn finish_file(&mut self) -> Result<()> {
+        self.format
+            .finish_file(&self.current_file, self.current_event)?;
 
-        Ok(())
+        self.manifest.record(
+            &self.harmonic_path,
+            ManifestEntry {
+                run: self.current_run,
+                path: self.current_path.clone(),
+                event_count: self.current_event,
+                content_hash: self.run_hasher.finalize(),
+            },
+        )
     }
 }